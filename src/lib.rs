@@ -20,6 +20,16 @@
 //! } // `print` will now be printed here.
 //! ```
 //!
+//! And used directly as the held value, via `Deref`/`DerefMut`, instead of going through `as_ref`/`as_mut`
+//! ```
+//! # use phantomdrop::PhantomDrop;
+//! fn do_something(values: Vec<i32>)
+//! {
+//!  let mut buf = PhantomDrop::new(values, |v| println!("Dropped: {:?}", v));
+//!  buf.push(1); // `buf` derefs to `Vec<i32>`
+//! } // `values` will now be printed here.
+//! ```
+//!
 //! Or capture a value, by reference, mutable reference, or moving.
 //! ```
 //! fn do_something(print: String)
@@ -39,26 +49,101 @@
 //!  let _guard = phantomdrop::defer(|| *print = String::from("Dropped")); // Holds a mutable reference to `print`.
 //! } // `print` will now be set to "Dropped" here.
 //! ```
+//!
+//! # `no_std`
+//! This crate is `no_std` by default: `PhantomDrop`, `defer`, `neutralise`, and `neutralise_in_place` only depend on `core`. Enable the `alloc` feature for the heap-allocating extras (`new_pinned`, `boxed`/`neutralise_boxed`, and the FFI bridge in `into_foreign`/`from_foreign`), or the `std` feature (which implies `alloc`) for the panic-aware `OnUnwind`/`OnSuccess` strategies.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")] extern crate alloc;
+
 use core::mem::MaybeUninit;
-use core::ops::Drop;
+use core::marker::PhantomData;
+use core::ops::{Drop, Deref, DerefMut};
+#[cfg(feature = "alloc")] use core::ffi::c_void;
+use core::pin::Pin;
+#[cfg(feature = "alloc")] use alloc::boxed::Box;
 
-/// When dropped, the included function is ran with the argument held by the structure.
+pub use strategy::{Strategy, Always, OnUnwind, OnSuccess};
+
+/// Strategies controlling *when* a guard's deferred function is run, based on how its scope exited.
+///
+/// Mirrors the `Always`/`OnSuccess`/`OnUnwind` strategies found in `scopeguard`, as zero-sized marker types rather than a runtime flag, so a guard using the default `Always` strategy stays as small as before.
+pub mod strategy
+{
+    /// Determines whether a guard's deferred function should run, given whether the scope is currently unwinding.
+    ///
+    /// # Notes
+    /// Without the `std` feature, `std::thread::panicking()` is unavailable, so `OnUnwind` and `OnSuccess` both fall back to `Always`'s behaviour (the function always runs).
+    pub trait Strategy
+    {
+	/// Whether the deferred function should be called, given whether we are currently unwinding from a panic.
+	fn should_run(panicking: bool) -> bool;
+    }
+
+    /// Always run the deferred function, regardless of how the scope exited. The default strategy.
+    #[derive(Debug)] pub enum Always {}
+    /// Only run the deferred function if the scope is exiting because of a panic.
+    #[derive(Debug)] pub enum OnUnwind {}
+    /// Only run the deferred function if the scope is exiting normally, i.e. *not* because of a panic.
+    #[derive(Debug)] pub enum OnSuccess {}
+
+    impl Strategy for Always
+    {
+	#[inline] fn should_run(_panicking: bool) -> bool
+	{
+	    true
+	}
+    }
+    impl Strategy for OnUnwind
+    {
+	#[cfg(feature = "std")] #[inline] fn should_run(panicking: bool) -> bool
+	{
+	    panicking
+	}
+	#[cfg(not(feature = "std"))] #[inline] fn should_run(_panicking: bool) -> bool
+	{
+	    true
+	}
+    }
+    impl Strategy for OnSuccess
+    {
+	#[cfg(feature = "std")] #[inline] fn should_run(panicking: bool) -> bool
+	{
+	    !panicking
+	}
+	#[cfg(not(feature = "std"))] #[inline] fn should_run(_panicking: bool) -> bool
+	{
+	    true
+	}
+    }
+}
+
+#[cfg(feature = "std")] #[inline(always)] fn is_panicking() -> bool
+{
+    std::thread::panicking()
+}
+#[cfg(not(feature = "std"))] #[inline(always)] fn is_panicking() -> bool
+{
+    false
+}
+
+/// When dropped, the included function is ran with the argument held by the structure, according to its `Strategy` `S`.
 ///
 /// # Notes
-/// If both the function and the value are zero-sized (unique non-capturing closures are ZSTs), this wrapper will also be zero-sized.
+/// If both the function and the value are zero-sized (unique non-capturing closures are ZSTs), this wrapper will also be zero-sized, regardless of `S`.
 #[derive(Debug)]
-pub struct PhantomDrop<T, F: FnOnce(T)>(MaybeUninit<(T, F)>);
+pub struct PhantomDrop<T, F: FnOnce(T), S: Strategy = Always>(MaybeUninit<(T, F)>, PhantomData<S>);
 
-impl<T: Clone, F: Clone + FnOnce(T)> Clone for PhantomDrop<T,F>
+impl<T: Clone, F: Clone + FnOnce(T), S: Strategy> Clone for PhantomDrop<T,F,S>
 {
     #[inline] fn clone(&self) -> Self
     {
 	let re = unsafe { self.value_ref() };
-	Self(MaybeUninit::new((re.0.clone(), re.1.clone())))
+	Self(MaybeUninit::new((re.0.clone(), re.1.clone())), PhantomData)
     }
 }
 
-impl<F> PhantomDrop<(),F>
+impl<F> PhantomDrop<(),F,Always>
 where F: FnOnce(())
 {
     /// Defer a function to run when this guard is dropped.
@@ -68,13 +153,92 @@ where F: FnOnce(())
     }
 }
 
+impl<F> PhantomDrop<(),F,OnUnwind>
+where F: FnOnce(())
+{
+    /// Defer a function to run only if this guard is dropped while unwinding from a panic.
+    #[inline] pub fn defer_on_unwind(fun: F) -> Self
+    {
+	PhantomDrop::new_strategy((), fun)
+    }
+}
+
+impl<F> PhantomDrop<(),F,OnSuccess>
+where F: FnOnce(())
+{
+    /// Defer a function to run only if this guard is dropped without unwinding, i.e. the scope exited successfully.
+    #[inline] pub fn defer_on_success(fun: F) -> Self
+    {
+	PhantomDrop::new_strategy((), fun)
+    }
+}
+
 /// Defer this function to run when the returned guard is dropped.
 pub fn defer(fun: impl FnOnce()) -> PhantomDrop<(), impl FnOnce(())>
 {
     PhantomDrop::defer(move |_| fun())
 }
 
-impl<T, F> PhantomDrop<T,F>
+/// Defer this function to run only if the returned guard is dropped while unwinding from a panic.
+pub fn defer_on_unwind(fun: impl FnOnce()) -> PhantomDrop<(), impl FnOnce(()), OnUnwind>
+{
+    PhantomDrop::defer_on_unwind(move |_| fun())
+}
+
+/// Defer this function to run only if the returned guard is dropped without unwinding, i.e. the scope exited successfully.
+pub fn defer_on_success(fun: impl FnOnce()) -> PhantomDrop<(), impl FnOnce(()), OnSuccess>
+{
+    PhantomDrop::defer_on_success(move |_| fun())
+}
+
+/// Defer a block of statements, or a comma-separated list of expressions, to run when the current scope ends, similar to Go's `defer`.
+///
+/// Unlike calling [`defer()`] directly, this generates a hidden, uniquely-scoped guard binding, so several `defer!`s can stack LIFO in the same block without naming collisions.
+/// ```
+/// use phantomdrop::defer;
+/// fn do_something()
+/// {
+///     defer!{ println!("flushing"); println!("done"); }
+///     defer!(println!("first"));
+///     // `first` prints, then `flushing`/`done`, as the scope unwinds in reverse declaration order.
+/// }
+/// ```
+#[macro_export]
+macro_rules! defer
+{
+    ( $($expr:expr),+ $(,)? ) => {
+	let _phantomdrop_guard = $crate::defer(move || { $($expr);+; });
+    };
+    ( $($body:tt)* ) => {
+	let _phantomdrop_guard = $crate::defer(move || { $($body)* });
+    };
+}
+
+/// As [`defer!`], but the deferred code only runs if the scope is dropped while unwinding from a panic. See [`defer_on_unwind()`].
+#[macro_export]
+macro_rules! defer_on_unwind
+{
+    ( $($expr:expr),+ $(,)? ) => {
+	let _phantomdrop_guard = $crate::defer_on_unwind(move || { $($expr);+; });
+    };
+    ( $($body:tt)* ) => {
+	let _phantomdrop_guard = $crate::defer_on_unwind(move || { $($body)* });
+    };
+}
+
+/// As [`defer!`], but the deferred code only runs if the scope is dropped without unwinding. See [`defer_on_success()`].
+#[macro_export]
+macro_rules! defer_on_success
+{
+    ( $($expr:expr),+ $(,)? ) => {
+	let _phantomdrop_guard = $crate::defer_on_success(move || { $($expr);+; });
+    };
+    ( $($body:tt)* ) => {
+	let _phantomdrop_guard = $crate::defer_on_success(move || { $($body)* });
+    };
+}
+
+impl<T, F, S: Strategy> PhantomDrop<T,F,S>
 where F: FnOnce(T)
 {
     #[inline(always)] unsafe fn value_mut(&mut self) -> &mut (T, F)
@@ -91,11 +255,11 @@ where F: FnOnce(T)
 	core::mem::forget(self);
 	(v, f)
     }
-    
-    /// Defer a function to run on this stored value when this guard is 
-    #[inline] pub fn new(value: T, fun: F) -> Self
+
+    /// Defer a function to run on this stored value when this guard is dropped, according to an explicit `Strategy` `S`.
+    #[inline] pub fn new_strategy(value: T, fun: F) -> Self
     {
-	Self(MaybeUninit::new((value, fun)))
+	Self(MaybeUninit::new((value, fun)), PhantomData)
     }
 
     /// Consume the instance into its held type without running the drop closure.
@@ -124,6 +288,22 @@ where F: FnOnce(T)
 	unsafe { &self.value_ref().0 }
     }
 
+    /// Get a pinned mutable reference to the held type, for guards constructed with `new_pinned` (or otherwise already pinned).
+    ///
+    /// This is the pin-respecting counterpart to `as_mut`: since `PhantomDrop<T,F,S>` is only `Unpin` when `T` and `F` both are, a `!Unpin` held value can only be reached through here once behind a `Pin`, never moved out.
+    #[inline] pub fn as_pin_mut(self: Pin<&mut Self>) -> Pin<&mut T>
+    {
+	unsafe { self.map_unchecked_mut(|this| this.as_mut()) }
+    }
+
+    /// Defer a function to run on this stored value when this guard is dropped, pinning both in place on the heap.
+    ///
+    /// Use this instead of `new_strategy` when `T` must not move once constructed (intrusive lists, FFI handles registered by address, futures): the returned guard is only reachable through `Pin`, so a `!Unpin` `T` can never be moved out except by `drop`, which reads it out of the `MaybeUninit` once, in place, and runs `fun` on it as usual.
+    #[cfg(feature = "alloc")] #[inline] pub fn new_pinned(value: T, fun: F) -> Pin<Box<Self>>
+    {
+	Box::pin(Self::new_strategy(value, fun))
+    }
+
     /// Replace the function to be ran on drop with a no-op.
     #[inline] pub fn neutralise(self) -> PhantomDrop<T, fn (T)>
     {
@@ -132,13 +312,58 @@ where F: FnOnce(T)
 
 }
 
-impl<T: 'static> PhantomDrop<T, Box<dyn FnOnce(T)>>
+impl<T, F> PhantomDrop<T,F,Always>
+where F: FnOnce(T)
+{
+    /// Defer a function to run on this stored value when this guard is dropped.
+    #[inline] pub fn new(value: T, fun: F) -> Self
+    {
+	PhantomDrop::new_strategy(value, fun)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<T: 'static, F: 'static, S: Strategy> PhantomDrop<T,F,S>
+where F: FnOnce(T)
+{
+    /// Move this guard across an FFI boundary as an opaque pointer, so foreign code can own it and, e.g., register it as a `void (*)(void*)` destructor callback that reconstructs it with `from_foreign` to run its cleanup.
+    ///
+    /// # Notes
+    /// Every pointer returned from here must be reclaimed by exactly one call to `from_foreign`, or the boxed value (and whatever it would have cleaned up) leaks. Do not mix a leaked foreign pointer with `forget`/`neutralise` on a reconstructed guard elsewhere, as both would then believe they own it. `F` is part of the pointer's type on the far side, so an unnameable closure type must be erased first, e.g. by constructing the guard with a `Box<dyn FnOnce(T)>` as its function.
+    #[inline] pub fn into_foreign(self) -> *mut c_void
+    {
+	let parts = unsafe { self.into_raw_parts() };
+	Box::into_raw(Box::new(parts)) as *mut c_void
+    }
+
+    /// Reconstruct a guard previously handed across an FFI boundary with `into_foreign`. Its drop closure will run as usual when the returned guard is dropped.
+    ///
+    /// # Safety
+    /// `ptr` must have been produced by `into_foreign` on a `PhantomDrop<T, F, S>` with the same `T`, `F` and `S`, and must not already have been reclaimed by a previous `from_foreign`.
+    #[inline] pub unsafe fn from_foreign(ptr: *mut c_void) -> Self
+    {
+	let (v, f) = *Box::from_raw(ptr as *mut (T, F));
+	Self::new_strategy(v, f)
+    }
+
+    /// Borrow the value held by a foreign pointer, without taking ownership of it.
+    ///
+    /// # Safety
+    /// `ptr` must have been produced by `into_foreign` on a `PhantomDrop<T, F, S>` with the same `T` and `F`, and must not already have been reclaimed by `from_foreign`.
+    #[inline] pub unsafe fn borrow<'a>(ptr: *mut c_void) -> &'a T
+    {
+	&(*(ptr as *const (T, F))).0
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<T: 'static, S: Strategy> PhantomDrop<T, Box<dyn FnOnce(T)>, S>
 {
     /// Box the closure in this instance on to the heap.
-    #[inline] pub fn boxed(self) -> PhantomDrop<T, Box<dyn FnOnce(T)>>
-    {	
+    #[inline] pub fn boxed(self) -> PhantomDrop<T, Box<dyn FnOnce(T)>, S>
+    {
 	let (v, f) = unsafe { self.into_raw_parts() };
-	PhantomDrop::new(v, Box::new(f))
+	PhantomDrop::new_strategy(v, Box::new(f))
     }
 
     /// Replace the function to be ran on drop with a no-op in place on the heap.
@@ -147,7 +372,7 @@ impl<T: 'static> PhantomDrop<T, Box<dyn FnOnce(T)>>
 	unsafe { self.value_mut().1 = Box::new(drop) };
     }
 }
-impl<T> PhantomDrop<T, fn (T)>
+impl<T, S: Strategy> PhantomDrop<T, fn (T), S>
 {
     /// Replace the function to be ran on drop with a no-op in place with no allocations.
     #[inline] pub fn neutralise_in_place(&mut self)
@@ -157,17 +382,41 @@ impl<T> PhantomDrop<T, fn (T)>
 }
 
 
-impl<T, F> Drop for PhantomDrop<T,F>
+impl<T, F, S: Strategy> Drop for PhantomDrop<T,F,S>
 where F: FnOnce(T)
 {
     #[inline] fn drop(&mut self)
     {
 	let (v, f) = unsafe { self.0.as_ptr().read() };
-	f(v);
+	if S::should_run(is_panicking()) {
+	    f(v);
+	} else {
+	    drop(v);
+	}
+    }
+}
+
+impl<T, F, S: Strategy> Deref for PhantomDrop<T,F,S>
+where F: FnOnce(T)
+{
+    type Target = T;
+
+    #[inline] fn deref(&self) -> &T
+    {
+	self.as_ref()
+    }
+}
+
+impl<T, F, S: Strategy> DerefMut for PhantomDrop<T,F,S>
+where F: FnOnce(T)
+{
+    #[inline] fn deref_mut(&mut self) -> &mut T
+    {
+	self.as_mut()
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests
 {
     #[test]
@@ -223,4 +472,164 @@ mod tests
     {
 	let _guard = super::defer(|| println!("Hello!"));
     }
+    #[test]
+    #[cfg(feature = "std")]
+    fn on_success_runs_when_not_unwinding()
+    {
+	let mut ran = false;
+	{
+	    let _guard = super::PhantomDrop::<_,_,super::OnSuccess>::new_strategy(&mut ran, |flag| *flag = true);
+	}
+	assert!(ran);
+    }
+    #[test]
+    #[cfg(feature = "std")]
+    fn on_unwind_skips_when_not_unwinding()
+    {
+	let mut ran = false;
+	{
+	    let _guard = super::PhantomDrop::<_,_,super::OnUnwind>::new_strategy(&mut ran, |flag| *flag = true);
+	}
+	assert!(!ran);
+    }
+    #[test]
+    #[cfg(feature = "std")]
+    fn on_unwind_runs_when_unwinding()
+    {
+	use std::panic::{catch_unwind, AssertUnwindSafe};
+	let mut ran = false;
+	let _ = catch_unwind(AssertUnwindSafe(|| {
+	    let _guard = super::PhantomDrop::<_,_,super::OnUnwind>::new_strategy(&mut ran, |flag| *flag = true);
+	    panic!("unwind for test");
+	}));
+	assert!(ran);
+    }
+    #[test]
+    fn deref_transparent()
+    {
+	let mut buf = super::PhantomDrop::new(Vec::new(), |v: Vec<i32>| println!("{:?}", v));
+	buf.push(1);
+	buf.push(2);
+	assert_eq!(buf.len(), 2);
+    }
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn foreign_round_trip()
+    {
+	let fun: Box<dyn FnOnce(String)> = Box::new(|string| println!("Dropped: {}", string));
+	let ptr = super::PhantomDrop::new(String::from("Hello!"), fun).into_foreign();
+	assert_eq!(unsafe { super::PhantomDrop::<String, Box<dyn FnOnce(String)>>::borrow(ptr) }, "Hello!");
+	let guard = unsafe { super::PhantomDrop::<String, Box<dyn FnOnce(String)>>::from_foreign(ptr) };
+	assert_eq!(guard.as_ref(), "Hello!");
+    }
+    #[test]
+    fn defer_macro_block()
+    {
+	let order = std::cell::RefCell::new(Vec::new());
+	let order = &order;
+	{
+	    defer!{ order.borrow_mut().push(1); order.borrow_mut().push(2); }
+	}
+	assert_eq!(*order.borrow(), vec![1, 2]);
+    }
+    #[test]
+    fn defer_macro_expr_list()
+    {
+	let order = std::cell::RefCell::new(Vec::new());
+	let order = &order;
+	{
+	    defer!(order.borrow_mut().push(1), order.borrow_mut().push(2));
+	}
+	assert_eq!(*order.borrow(), vec![1, 2]);
+    }
+    #[test]
+    fn defer_macro_stacks_lifo()
+    {
+	let order = std::cell::RefCell::new(Vec::new());
+	let order = &order;
+	{
+	    defer!(order.borrow_mut().push(1));
+	    defer!(order.borrow_mut().push(2));
+	}
+	assert_eq!(*order.borrow(), vec![2, 1]);
+    }
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn pinned_runs_on_drop()
+    {
+	let mut ran = false;
+	{
+	    let _guard = super::PhantomDrop::<_,_>::new_pinned(&mut ran, |flag| *flag = true);
+	}
+	assert!(ran);
+    }
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn pinned_as_pin_mut()
+    {
+	let mut guard = super::PhantomDrop::<_,_>::new_pinned(Vec::new(), |v: Vec<i32>| println!("{:?}", v));
+	guard.as_mut().as_pin_mut().push(1);
+	guard.as_mut().as_pin_mut().push(2);
+	assert_eq!((*guard).as_ref(), &[1, 2]);
+    }
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn pinned_address_sensitive_value_survives_until_drop()
+    {
+	// `Anchor` is `!Unpin`, so this only compiles at all because `new_pinned`/`as_pin_mut`
+	// never require `T: Unpin` (unlike `DerefMut` through the `Pin`, which needs the guard to be `Unpin`).
+	use core::marker::PhantomPinned;
+	struct Anchor { value: i32, _pin: PhantomPinned }
+
+	let ran = std::cell::Cell::new(false);
+	let mut guard = super::PhantomDrop::<_,_>::new_pinned(
+	    Anchor { value: 42, _pin: PhantomPinned },
+	    |v| { ran.set(true); assert_eq!(v.value, 42); },
+	);
+
+	// The held value must stay at the same address across accesses, right up until drop.
+	let addr_before = &*guard.as_mut().as_pin_mut() as *const Anchor;
+	let addr_again = &*guard.as_mut().as_pin_mut() as *const Anchor;
+	assert_eq!(addr_before, addr_again);
+
+	drop(guard);
+	assert!(ran.get());
+    }
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn pinned_composes_with_strategy()
+    {
+	let mut ran = false;
+	{
+	    let _guard = super::PhantomDrop::<_,_,super::OnUnwind>::new_pinned(&mut ran, |flag| *flag = true);
+	}
+	assert!(!ran);
+    }
+}
+
+/// Coverage for the documented `not(feature = "std")` fallback (see `strategy::Strategy`'s notes), where
+/// `OnUnwind`/`OnSuccess` behave like `Always` because `std::thread::panicking()` is unavailable to consult.
+/// Kept separate from `mod tests` (which needs `std` for its types) so this path is actually exercised under
+/// plain `cargo test` with default features, rather than only asserted in prose.
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests
+{
+    #[test]
+    fn on_unwind_runs_without_std()
+    {
+	let mut ran = false;
+	{
+	    let _guard = super::PhantomDrop::<_,_,super::OnUnwind>::new_strategy(&mut ran, |flag| *flag = true);
+	}
+	assert!(ran);
+    }
+    #[test]
+    fn on_success_runs_without_std()
+    {
+	let mut ran = false;
+	{
+	    let _guard = super::PhantomDrop::<_,_,super::OnSuccess>::new_strategy(&mut ran, |flag| *flag = true);
+	}
+	assert!(ran);
+    }
 }